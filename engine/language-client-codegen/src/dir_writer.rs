@@ -1,11 +1,152 @@
 use anyhow::Result;
 use internal_baml_core::ir::repr::IntermediateRepr;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
 use std::io::ErrorKind;
 use std::path::Path;
 use std::thread::sleep;
 use std::time::Duration;
 use std::{collections::HashMap, path::PathBuf};
 
+/// At or below this many items, spinning up rayon's thread pool costs more than it
+/// saves; just do the work on the current thread.
+const PARALLEL_THRESHOLD: usize = 1;
+
+/// Directory (relative to `output_path`) that holds BAML's own bookkeeping, such as
+/// the manifest. Never counted as an unknown file or descended into by the unknown-
+/// file scan -- it's ours, not the user's and not something we generated from an IR.
+const MANIFEST_DIR: &str = ".baml";
+
+/// Relative path (from `output_path`) of the manifest BAML writes after every commit,
+/// mapping each generated file to a content hash so a later run can tell a
+/// hand-edited generated file apart from a pristine one.
+const MANIFEST_PATH: &str = ".baml/manifest.json";
+
+/// A base58-encoded blake3 hash of `contents`, used both for the values recorded in
+/// the on-disk manifest and to recompute what's actually sitting on disk for comparison.
+fn hash_contents(contents: &str) -> String {
+    bs58::encode(blake3::hash(contents.as_bytes()).as_bytes()).into_string()
+}
+
+/// Maps every file BAML generated on the last run to the hash of what it wrote, so
+/// we can tell a user-edited generated file apart from a pristine one without relying
+/// on a "generated by BAML" marker scan.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl Manifest {
+    /// Load the manifest from `output_path`, or an empty one if it doesn't exist yet
+    /// (first run, or an output directory from before manifests existed).
+    fn load(output_path: &Path) -> Manifest {
+        std::fs::read_to_string(output_path.join(MANIFEST_PATH))
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn write(&self, output_path: &Path) -> Result<()> {
+        let manifest_path = output_path.join(MANIFEST_PATH);
+        if let Some(parent) = manifest_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(manifest_path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Name of the file, read from the output root, that lets users mark paths as
+/// theirs so BAML never treats them as unknown or deletes them.
+const BAML_IGNORE_FILE: &str = ".bamlignore";
+
+/// Compiled `.bamlignore` patterns: a glob per line, matched against paths relative
+/// to the output root. A pattern also matches everything underneath it, so a line
+/// naming a directory excludes the whole subtree.
+struct BamlIgnore {
+    patterns: Vec<Regex>,
+    /// Patterns with no glob metacharacters name a directory outright, so the walk
+    /// can prune that whole subtree up front instead of matching every entry in it
+    /// (mirroring the `VisitChildrenSet`-style pruning step in Mercurial's matchers).
+    prune_dirs: Vec<PathBuf>,
+}
+
+impl BamlIgnore {
+    fn load(output_path: &Path) -> BamlIgnore {
+        let Ok(contents) = std::fs::read_to_string(output_path.join(BAML_IGNORE_FILE)) else {
+            return BamlIgnore {
+                patterns: vec![],
+                prune_dirs: vec![],
+            };
+        };
+
+        let mut patterns = vec![];
+        let mut prune_dirs = vec![];
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let literal = line.trim_end_matches("/**").trim_end_matches('/');
+            if !literal.is_empty() && !literal.contains(['*', '?', '[']) {
+                prune_dirs.push(PathBuf::from(literal));
+            }
+            patterns.push(glob_to_regex(line));
+        }
+        BamlIgnore {
+            patterns,
+            prune_dirs,
+        }
+    }
+
+    /// Whether `relative_path` (relative to the output root) is user-owned and
+    /// should never be counted as unknown or deleted.
+    fn is_ignored(&self, relative_path: &Path) -> bool {
+        let path_str = relative_path.to_string_lossy().replace('\\', "/");
+        self.patterns.iter().any(|pattern| pattern.is_match(&path_str))
+    }
+
+    /// Whether the walk should descend into `relative_dir` at all. Returning `false`
+    /// lets a whole subtree like `node_modules` be skipped without statting anything
+    /// inside it.
+    fn should_visit_dir(&self, relative_dir: &Path) -> bool {
+        !self
+            .prune_dirs
+            .iter()
+            .any(|dir| relative_dir == dir || relative_dir.starts_with(dir))
+    }
+}
+
+/// Translate a single `.bamlignore` glob line into an anchored regex: `*` matches
+/// within a path segment, `**` matches across segments, and a match on a directory
+/// also covers everything underneath it.
+fn glob_to_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c if r"\.+()|[]{}^$".contains(c) => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            c => pattern.push(c),
+        }
+    }
+    pattern.push_str("(/.*)?$");
+    Regex::new(&pattern).unwrap_or_else(|e| {
+        panic!("`.bamlignore` pattern {glob:?} compiled to an invalid regex: {e}")
+    })
+}
+
 // Add a trait per language that can be used to convert an Import into a string
 pub(super) trait LanguageFeatures {
     const CONTENT_PREFIX: &'static str;
@@ -22,6 +163,33 @@ pub(super) struct FileCollector<L: LanguageFeatures + Default> {
     lang: L,
 }
 
+/// The status of a single relative path after diffing the freshly generated files
+/// against whatever is already sitting in `output_path`, the same clean/modified/
+/// added/removed split a dirstate walk uses to decide what a commit actually has to touch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FileStatus {
+    /// Generated and on disk, with identical contents. Left untouched so its mtime
+    /// doesn't change and editor file watchers don't fire for no reason.
+    Unchanged,
+    /// Generated and on disk, but the contents differ. Rewritten in place.
+    Modified,
+    /// Generated but not yet on disk. Written for the first time.
+    Added,
+    /// On disk and BAML-generated, but no longer part of the generated set. Deleted.
+    Removed,
+}
+
+/// A dirstate-style classification of every path touched by a commit, keyed by path
+/// relative to `output_path`.
+pub(super) type FileStatuses = BTreeMap<PathBuf, FileStatus>;
+
+/// The result of classifying a single on-disk path, before it's folded into the
+/// overall [`FileStatuses`]/edited-file accounting.
+enum ClassifyOutcome {
+    Status(FileStatus),
+    EditedByUser,
+}
+
 fn try_delete_tmp_dir(temp_path: &Path) -> Result<()> {
     // if the .tmp dir exists, delete it so we can get back to a working state without user intervention.
     let delete_attempts = 3; // Number of attempts to delete the directory
@@ -109,38 +277,62 @@ impl<L: LanguageFeatures + Default> FileCollector<L> {
     /// in the first 1024 bytes, and limit our search to a max of N unrecognized files.
     /// This gives us performance bounds if, for example, we find ourselves iterating
     /// through node_modules or .pycache or some other thing.
-    fn remove_dir_safe(&self, output_path: &Path) -> Result<()> {
+    ///
+    /// Paths matched by a `.bamlignore` pattern at the output root are user-owned:
+    /// they're never counted as unknown, and are moved into `temp_path` -- so they
+    /// survive into the new tree -- instead of being wiped along with everything
+    /// else. Whole directories `.bamlignore` names are moved as a unit rather than
+    /// being statted entry by entry.
+    fn remove_dir_safe(&self, output_path: &Path, temp_path: &Path) -> Result<()> {
         if !output_path.exists() {
             return Ok(());
         }
 
-        const MAX_UNKNOWN_FILES: usize = 4;
-        let mut unknown_files = vec![];
-        for entry in walkdir::WalkDir::new(output_path)
-            .into_iter()
-            .filter_entry(|e| e.path().file_name().is_some_and(|f| f != "__pycache__"))
-        {
-            if unknown_files.len() > MAX_UNKNOWN_FILES {
-                break;
-            }
+        let ignore = BamlIgnore::load(output_path);
+
+        // The walk itself is cheap (just stats), so it stays serial; only the actual
+        // marker scan below -- which reads every candidate file -- is parallelized.
+        let mut candidates = vec![];
+        let mut ignored_paths = vec![];
+        let mut walker = walkdir::WalkDir::new(output_path).into_iter();
+        while let Some(entry) = walker.next() {
             let entry = entry?;
-            if entry.file_type().is_dir() {
-                // Only files matter for the pre-existence check
+            let relative_path = match entry.path().strip_prefix(output_path) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative.to_path_buf(),
+                _ => continue, // `output_path` itself
+            };
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name == "__pycache__" || name == MANIFEST_DIR)
+            {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
                 continue;
             }
-            let path = entry.path();
-            if let Ok(mut f) = std::fs::File::open(&path) {
-                use std::io::Read;
-                let mut buf = [0; 1024];
-                if f.read(&mut buf).is_ok()
-                    && String::from_utf8_lossy(&buf).contains("generated by BAML")
-                {
-                    continue;
+            if entry.file_type().is_dir() {
+                if !ignore.should_visit_dir(&relative_path) {
+                    ignored_paths.push(relative_path);
+                    walker.skip_current_dir();
                 }
+                continue;
+            }
+            if ignore.is_ignored(&relative_path) {
+                ignored_paths.push(relative_path);
+                continue;
             }
-            let path = path.strip_prefix(output_path)?.to_path_buf();
-            unknown_files.push(path);
+            candidates.push(relative_path);
         }
+
+        let is_unknown = |relative_path: &PathBuf| !contains_baml_marker(&output_path.join(relative_path));
+        let mut unknown_files: Vec<PathBuf> = if candidates.len() > PARALLEL_THRESHOLD {
+            candidates.into_par_iter().filter(is_unknown).collect()
+        } else {
+            candidates.into_iter().filter(is_unknown).collect()
+        };
+
+        const MAX_DISPLAYED_UNKNOWN_FILES: usize = 4;
         unknown_files.sort();
         match unknown_files.len() {
             0 => (),
@@ -150,62 +342,319 @@ impl<L: LanguageFeatures + Default> FileCollector<L> {
                 File: {}",
                 output_path.join(&unknown_files[0]).display()
             ),
-            n => {
-                if n < MAX_UNKNOWN_FILES {
-                    anyhow::bail!(
-                        "output directory contains {n} files that BAML did not generate\n\n\
-                    Please remove them and re-run codegen.\n\n\
-                    Files:\n{}",
-                        unknown_files
-                            .iter()
-                            .map(|p| format!("  - {}", output_path.join(p).display()))
-                            .collect::<Vec<_>>()
-                            .join("\n")
-                    )
-                } else {
-                    anyhow::bail!(
-                        "output directory contains at least {n} files that BAML did not generate\n\n\
-                    Please remove all files not generated by BAML and re-run codegen.\n\n\
-                    Files:\n{}",
-                        unknown_files
-                            .iter()
-                            .map(|p| format!("  - {}", output_path.join(p).display()))
-                            .collect::<Vec<_>>()
-                            .join("\n")
-                    )
-                }
+            n => anyhow::bail!(
+                "output directory contains {n} files that BAML did not generate\n\n\
+                Please remove them and re-run codegen.\n\n\
+                Files:\n{}",
+                unknown_files
+                    .iter()
+                    .take(MAX_DISPLAYED_UNKNOWN_FILES)
+                    .map(|p| format!("  - {}", output_path.join(p).display()))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        }
+
+        // Carry `.bamlignore`d paths over into the new tree before wiping this one,
+        // so they're never deleted by the swap.
+        for relative_path in ignored_paths {
+            let dest = temp_path.join(&relative_path);
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
             }
+            std::fs::rename(output_path.join(&relative_path), dest)?;
         }
+
         std::fs::remove_dir_all(output_path)?;
         Ok(())
     }
 
-    pub(super) fn commit(&self, output_path: &Path) -> Result<Vec<PathBuf>> {
+    /// Classify every path we care about relative to what's already on disk: anything
+    /// we generated is `Added`/`Modified`/`Unchanged` depending on what's there now,
+    /// and anything BAML previously generated but no longer generates is `Removed`.
+    /// Paths on disk that aren't ours (foreign files, `.bamlignore`d files, etc.) are
+    /// left out of the map entirely and never touched.
+    ///
+    /// If the manifest says we generated a path with different contents than what's
+    /// on disk now, the user edited it by hand since our last run: that path is
+    /// collected and reported as an error, unless `force` is set, in which case it's
+    /// treated the same as any other file we generated.
+    fn classify(&self, output_path: &Path, manifest: &Manifest, force: bool) -> Result<FileStatuses> {
+        let mut statuses: FileStatuses = self
+            .files
+            .keys()
+            .map(|path| (path.clone(), FileStatus::Added))
+            .collect();
+        let mut edited_by_user = vec![];
+
+        if output_path.exists() {
+            let ignore = BamlIgnore::load(output_path);
+
+            // Same split as `remove_dir_safe`: the walk is a cheap serial stat pass,
+            // and only the hashing below -- which reads every candidate file -- is
+            // parallelized.
+            let mut candidates = vec![];
+            for entry in walkdir::WalkDir::new(output_path).into_iter().filter_entry(|e| {
+                e.path().file_name().is_some_and(|f| f != "__pycache__" && f != MANIFEST_DIR)
+                    && e.path()
+                        .strip_prefix(output_path)
+                        .is_ok_and(|relative| relative == Path::new("") || ignore.should_visit_dir(relative))
+            }) {
+                let entry = entry?;
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+                let relative_path = entry.path().strip_prefix(output_path)?.to_path_buf();
+                if ignore.is_ignored(&relative_path) {
+                    // User-owned: never treated as unknown, never deleted.
+                    continue;
+                }
+                candidates.push(relative_path);
+            }
+            // A generated path that's `.bamlignore`d and already on disk belongs to the
+            // user: drop it so it's never overwritten.
+            statuses.retain(|path, _| !(ignore.is_ignored(path) && output_path.join(path).exists()));
+
+            let classify_one = |relative_path: PathBuf| -> Result<(PathBuf, Option<ClassifyOutcome>)> {
+                let on_disk_bytes = std::fs::read(output_path.join(&relative_path))?;
+                // BAML only ever generates UTF-8 text, so a file we can't decode as
+                // UTF-8 is never one of ours -- treat it like any other foreign file
+                // instead of letting the decode error abort the whole commit.
+                let Ok(on_disk_contents) = String::from_utf8(on_disk_bytes) else {
+                    return Ok((relative_path, None));
+                };
+                let edited_by_user = manifest
+                    .files
+                    .get(&relative_path)
+                    .is_some_and(|last_known_hash| {
+                        !force && &hash_contents(&on_disk_contents) != last_known_hash
+                    });
+                if edited_by_user {
+                    return Ok((relative_path, Some(ClassifyOutcome::EditedByUser)));
+                }
+
+                let outcome = match self.files.get(&relative_path) {
+                    Some(generated_contents) => Some(ClassifyOutcome::Status(
+                        if &on_disk_contents == generated_contents {
+                            FileStatus::Unchanged
+                        } else {
+                            FileStatus::Modified
+                        },
+                    )),
+                    None if manifest.files.contains_key(&relative_path) => {
+                        Some(ClassifyOutcome::Status(FileStatus::Removed))
+                    }
+                    None => None,
+                };
+                Ok((relative_path, outcome))
+            };
+
+            let results: Vec<Result<(PathBuf, Option<ClassifyOutcome>)>> =
+                if candidates.len() > PARALLEL_THRESHOLD {
+                    candidates.into_par_iter().map(classify_one).collect()
+                } else {
+                    candidates.into_iter().map(classify_one).collect()
+                };
+
+            for result in results {
+                let (relative_path, outcome) = result?;
+                match outcome {
+                    Some(ClassifyOutcome::Status(status)) => {
+                        statuses.insert(relative_path, status);
+                    }
+                    Some(ClassifyOutcome::EditedByUser) => edited_by_user.push(relative_path),
+                    None => {}
+                }
+            }
+        }
+
+        edited_by_user.sort();
+        match edited_by_user.len() {
+            0 => (),
+            1 => anyhow::bail!(
+                "a BAML-generated file has been edited by hand since it was last generated\n\n\
+                Please revert your changes, or re-run codegen with `force` to overwrite it.\n\n\
+                File: {}",
+                output_path.join(&edited_by_user[0]).display()
+            ),
+            n => anyhow::bail!(
+                "{n} BAML-generated files have been edited by hand since they were last generated\n\n\
+                Please revert your changes, or re-run codegen with `force` to overwrite them.\n\n\
+                Files:\n{}",
+                edited_by_user
+                    .iter()
+                    .map(|p| format!("  - {}", output_path.join(p).display()))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        }
+
+        Ok(statuses)
+    }
+
+    /// Bring `output_path` in line with `self.files`, writing only the files that are
+    /// new or changed, deleting the ones we generated before but no longer generate,
+    /// and leaving everything else (including files that are already up to date)
+    /// untouched so their mtimes are stable for editors and file watchers.
+    ///
+    /// Aborts if any previously generated file was edited by hand since the last run,
+    /// unless `force` is set.
+    pub(super) fn commit(&self, output_path: &Path, force: bool) -> Result<FileStatuses> {
         log::debug!("Writing files to {}", output_path.display());
 
+        let manifest = Manifest::load(output_path);
+        let statuses = self.classify(output_path, &manifest, force)?;
+
+        let write_one = |(relative_path, status): (&PathBuf, &FileStatus)| -> io::Result<()> {
+            let full_path = output_path.join(relative_path);
+            match status {
+                FileStatus::Unchanged => Ok(()),
+                FileStatus::Added | FileStatus::Modified => {
+                    if let Some(parent) = full_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::write(&full_path, &self.files[relative_path])
+                }
+                FileStatus::Removed => {
+                    std::fs::remove_file(&full_path)?;
+                    prune_empty_ancestors(&full_path, output_path);
+                    Ok(())
+                }
+            }
+        };
+        // Every write targets a distinct path, so there's no cross-file contention;
+        // report the first failure if any file couldn't be written.
+        if statuses.len() > PARALLEL_THRESHOLD {
+            statuses
+                .par_iter()
+                .map(write_one)
+                .collect::<io::Result<Vec<()>>>()?;
+        } else {
+            for entry in &statuses {
+                write_one(entry)?;
+            }
+        }
+
+        Manifest {
+            files: self
+                .files
+                .iter()
+                .map(|(path, contents)| (path.clone(), hash_contents(contents)))
+                .collect(),
+        }
+        .write(output_path)?;
+
+        log::info!(
+            "Synced {} generated files to {} ({})",
+            self.files.len(),
+            output_path.display(),
+            summarize(&statuses)
+        );
+
+        Ok(statuses)
+    }
+
+    /// Force a full rebuild: write every file into a fresh `.tmp` directory, verify
+    /// `output_path` contains nothing but BAML-generated files (apart from
+    /// `.bamlignore`d paths, which are carried over into the `.tmp` directory), then
+    /// delete `output_path` and rename the `.tmp` directory into its place. This is
+    /// slower than [`FileCollector::commit`] (every file is rewritten, so mtimes and
+    /// file watchers churn) but is a reasonable escape hatch if incremental state is
+    /// ever suspected to have drifted. Every generated file is reported as `Added`,
+    /// since the whole tree is written anew.
+    pub(super) fn commit_clean(&self, output_path: &Path) -> Result<FileStatuses> {
+        log::debug!("Rebuilding {} from scratch", output_path.display());
+
         let temp_path = PathBuf::from(format!("{}.tmp", output_path.display()));
 
         // if the .tmp dir exists, delete it so we can get back to a working state without user intervention.
         try_delete_tmp_dir(temp_path.as_path())?;
 
-        // Sort the files by path so that we always write to the same file
-        for (relative_file_path, contents) in self.files.iter() {
+        let write_one = |(relative_file_path, contents): (&PathBuf, &String)| -> io::Result<()> {
             let full_file_path = temp_path.join(relative_file_path);
             if let Some(parent) = full_file_path.parent() {
                 std::fs::create_dir_all(parent)?;
             }
-            std::fs::write(&full_file_path, contents)?;
+            std::fs::write(&full_file_path, contents)
+        };
+        // Every file lands under a distinct path in `temp_path`, so there's no
+        // cross-file contention; report the first failure if any file couldn't be
+        // written.
+        if self.files.len() > PARALLEL_THRESHOLD {
+            self.files
+                .par_iter()
+                .map(write_one)
+                .collect::<io::Result<Vec<()>>>()?;
+        } else {
+            for entry in &self.files {
+                write_one(entry)?;
+            }
         }
 
-        self.remove_dir_safe(output_path)?;
+        self.remove_dir_safe(output_path, &temp_path)?;
         std::fs::rename(&temp_path, output_path)?;
 
+        Manifest {
+            files: self
+                .files
+                .iter()
+                .map(|(path, contents)| (path.clone(), hash_contents(contents)))
+                .collect(),
+        }
+        .write(output_path)?;
+
         log::info!(
             "Wrote {} files to {}",
             self.files.len(),
             output_path.display()
         );
 
-        Ok(self.files.keys().cloned().collect())
+        Ok(self
+            .files
+            .keys()
+            .map(|path| (path.clone(), FileStatus::Added))
+            .collect())
+    }
+}
+
+/// After deleting `removed_file`, remove any now-empty parent directories back up to
+/// (but not including) `output_path`, so deleting the last generated file in a
+/// subpackage doesn't leave a stale empty directory behind. Best-effort: stops at the
+/// first directory that isn't empty (or already gone), and ignores any other error.
+fn prune_empty_ancestors(removed_file: &Path, output_path: &Path) {
+    let mut dir = removed_file.parent();
+    while let Some(current) = dir {
+        if current == output_path || !current.starts_with(output_path) {
+            break;
+        }
+        if std::fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/// Best-effort check for whether a file was generated by BAML: does it contain the
+/// "generated by BAML" marker in the first 1024 bytes.
+fn contains_baml_marker(path: &Path) -> bool {
+    let Ok(mut f) = std::fs::File::open(path) else {
+        return false;
+    };
+    use std::io::Read;
+    let mut buf = [0; 1024];
+    f.read(&mut buf).is_ok() && String::from_utf8_lossy(&buf).contains("generated by BAML")
+}
+
+/// Render a `+added ~modified -removed` summary of a classification, for logging.
+fn summarize(statuses: &FileStatuses) -> String {
+    let (mut added, mut modified, mut removed) = (0, 0, 0);
+    for status in statuses.values() {
+        match status {
+            FileStatus::Added => added += 1,
+            FileStatus::Modified => modified += 1,
+            FileStatus::Removed => removed += 1,
+            FileStatus::Unchanged => {}
+        }
     }
+    format!("+{added} ~{modified} -{removed}")
 }